@@ -4,6 +4,7 @@ extern crate sha1;
 extern crate rustc_serialize;
 
 use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::cell::RefCell;
 use std::rc::Rc;
 use std::fmt;
@@ -26,6 +27,17 @@ fn gen_key(key: &String) -> String {
     buf.to_base64(STANDARD)
 }
 
+// What the handshake callback decides for a given upgrade request: accept
+// it (optionally tacking on extra response headers, e.g. a negotiated
+// `Sec-WebSocket-Protocol`) or reject it outright with an HTTP status
+// before ever switching protocols.
+enum HandshakeResponse {
+    Accept(Vec<(String, String)>),
+    Reject(u16, String),
+}
+
+type HandshakeCallback = Rc<Fn(&HashMap<String, String>) -> HandshakeResponse>;
+
 struct HttpParser {
     current_key: Option<String>,
     headers: Rc<RefCell<HashMap<String, String>>>,
@@ -33,16 +45,32 @@ struct HttpParser {
 
 impl ParserHandler for HttpParser {
     fn on_header_field(&mut self, s: &[u8]) -> bool {
-        self.current_key = Some(std::str::from_utf8(s).unwrap().to_string());
-        true
+        // Header names/values are raw bytes off the wire; a peer is free to
+        // send something that isn't valid UTF-8. Abort parsing instead of
+        // unwrap()-ing our way into a panic that takes the whole event
+        // loop down.
+        match std::str::from_utf8(s) {
+            Ok(key) => {
+                self.current_key = Some(key.to_string());
+                true
+            }
+            Err(_) => false,
+        }
     }
 
     fn on_header_value(&mut self, s: &[u8]) -> bool {
-        self.headers
-            .borrow_mut()
-            .insert(self.current_key.clone().unwrap(),
-                    std::str::from_utf8(s).unwrap().to_string());
-        true
+        let value = match std::str::from_utf8(s) {
+            Ok(value) => value.to_string(),
+            Err(_) => return false,
+        };
+
+        match self.current_key.clone() {
+            Some(key) => {
+                self.headers.borrow_mut().insert(key, value);
+                true
+            }
+            None => false,
+        }
     }
 
     fn on_headers_complete(&mut self) -> bool {
@@ -50,11 +78,196 @@ impl ParserHandler for HttpParser {
     }
 }
 
+// Anything that can go wrong while servicing a single client. Callers map
+// this to "close just this connection" rather than letting it take the
+// whole event loop down.
+#[derive(Debug)]
+enum ClientError {
+    Io(std::io::Error),
+    Protocol(&'static str),
+}
+
+impl std::convert::From<std::io::Error> for ClientError {
+    fn from(err: std::io::Error) -> ClientError {
+        ClientError::Io(err)
+    }
+}
+
+// The low seven bits of the second header byte, used both as the
+// length itself and as a marker for the two extended-length encodings.
+const PAYLOAD_LEN_U16: u8 = 126;
+const PAYLOAD_LEN_U64: u8 = 127;
+const MASK_KEY_LEN: usize = 4;
+
+// A peer using the 127-length encoding can claim a payload up to u64::MAX
+// without overflowing anything; left unchecked that's still an invitation
+// to have this process allocate and `to_vec()` gigabytes for one frame.
+// Nothing in this chat protocol needs a single frame bigger than this.
+const MAX_FRAME_PAYLOAD_LEN: u64 = 16 * 1024 * 1024;
+
+// A single frame's payload is already capped above, but a peer can still
+// string together unlimited continuation frames (FIN never set) to grow
+// `fragment_buffer` without bound. Cap the reassembled message too.
+const MAX_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OpCode {
+    ContinuationFrame,
+    TextFrame,
+    BinaryFrame,
+    ConnectionClose,
+    Ping,
+    Pong,
+}
+
+impl OpCode {
+    fn from_u8(byte: u8) -> Option<OpCode> {
+        match byte & 0x0F {
+            0x0 => Some(OpCode::ContinuationFrame),
+            0x1 => Some(OpCode::TextFrame),
+            0x2 => Some(OpCode::BinaryFrame),
+            0x8 => Some(OpCode::ConnectionClose),
+            0x9 => Some(OpCode::Ping),
+            0xA => Some(OpCode::Pong),
+            _ => None,
+        }
+    }
+}
+
+struct WebSocketFrame {
+    fin: bool,
+    opcode: OpCode,
+    payload: Vec<u8>,
+}
+
+impl WebSocketFrame {
+    // Tries to pull a single, fully-buffered frame off the front of `buf`.
+    // Returns the frame and the number of bytes it consumed, or `Ok(None)`
+    // if `buf` doesn't hold a complete frame yet (the caller should wait
+    // for more bytes and try again). An `Err` means `buf` starts with
+    // something that isn't a valid frame at all, not just an incomplete one.
+    fn parse(buf: &[u8]) -> Result<Option<(WebSocketFrame, usize)>, ClientError> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+
+        let fin = buf[0] & 0x80 != 0;
+        let opcode = match OpCode::from_u8(buf[0]) {
+            Some(opcode) => opcode,
+            None => return Err(ClientError::Protocol("unknown frame opcode")),
+        };
+
+        let masked = buf[1] & 0x80 != 0;
+        let mut payload_len = (buf[1] & 0x7F) as u64;
+        let mut pos = 2;
+
+        if payload_len == PAYLOAD_LEN_U16 as u64 {
+            if buf.len() < pos + 2 {
+                return Ok(None);
+            }
+            payload_len = ((buf[pos] as u64) << 8) | (buf[pos + 1] as u64);
+            pos += 2;
+        } else if payload_len == PAYLOAD_LEN_U64 as u64 {
+            if buf.len() < pos + 8 {
+                return Ok(None);
+            }
+            payload_len = 0;
+            for i in 0..8 {
+                payload_len = (payload_len << 8) | (buf[pos + i] as u64);
+            }
+            pos += 8;
+        }
+
+        if payload_len > MAX_FRAME_PAYLOAD_LEN {
+            return Err(ClientError::Protocol("frame payload length exceeds maximum"));
+        }
+
+        // Client frames must be masked; anything else is a protocol
+        // violation rather than a frame we just haven't fully seen yet.
+        if !masked {
+            return Err(ClientError::Protocol("client frame missing mask bit"));
+        }
+
+        if buf.len() < pos + MASK_KEY_LEN {
+            return Ok(None);
+        }
+        let mask = [buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]];
+        pos += MASK_KEY_LEN;
+
+        // `payload_len` came straight off the wire; the cap above already
+        // bounds it, but the cast to `usize` and the addition below are still
+        // checked, so an overflow is a protocol violation instead of a panic
+        // in the event loop.
+        if payload_len > usize::max_value() as u64 {
+            return Err(ClientError::Protocol("frame payload length too large"));
+        }
+        let payload_len = payload_len as usize;
+
+        let end = match pos.checked_add(payload_len) {
+            Some(end) => end,
+            None => return Err(ClientError::Protocol("frame payload length overflows")),
+        };
+        if buf.len() < end {
+            return Ok(None);
+        }
+
+        let mut payload = buf[pos..end].to_vec();
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % MASK_KEY_LEN];
+        }
+
+        Ok(Some((WebSocketFrame {
+            fin: fin,
+            opcode: opcode,
+            payload: payload,
+        }, pos + payload_len)))
+    }
+
+    // Encodes a complete, unmasked, single-frame (FIN set) server-to-client
+    // message. Servers must not mask their frames, so there's no masking
+    // key to write.
+    fn encode(opcode: OpCode, payload: &[u8]) -> Vec<u8> {
+        let opcode_byte = match opcode {
+            OpCode::ContinuationFrame => 0x0,
+            OpCode::TextFrame => 0x1,
+            OpCode::BinaryFrame => 0x2,
+            OpCode::ConnectionClose => 0x8,
+            OpCode::Ping => 0x9,
+            OpCode::Pong => 0xA,
+        };
+
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode_byte);
+
+        let len = payload.len();
+        if len < PAYLOAD_LEN_U16 as usize {
+            frame.push(len as u8);
+        } else if len <= u16::max_value() as usize {
+            frame.push(PAYLOAD_LEN_U16);
+            frame.push((len >> 8) as u8);
+            frame.push(len as u8);
+        } else {
+            frame.push(PAYLOAD_LEN_U64);
+            for i in (0..8).rev() {
+                frame.push((len >> (i * 8)) as u8);
+            }
+        }
+
+        frame.extend_from_slice(payload);
+        frame
+    }
+}
+
 #[derive(PartialEq)]
 enum ClientState {
     AwaitingHandshake,
     HandshakeResponse,
     Connected,
+
+    // We've received a Close frame and queued our reply, or rejected the
+    // handshake and queued the HTTP error response; once the queue is
+    // flushed the server tears the connection down.
+    Closing,
 }
 
 struct WebSocketClient {
@@ -63,66 +276,230 @@ struct WebSocketClient {
     http_parser: Parser<HttpParser>,
     interest: EventSet,
     state: ClientState,
+    handshake_callback: HandshakeCallback,
+
+    // Raw bytes read off the socket while in the `Connected` state that
+    // haven't formed a complete frame yet.
+    read_buffer: Vec<u8>,
+
+    // Payloads of a fragmented message (opcode 0x0 continuations) collected
+    // so far, waiting for a frame with FIN set.
+    fragment_buffer: Vec<u8>,
+
+    // Whole messages decoded from the socket, waiting to be picked up by
+    // the server.
+    incoming_messages: VecDeque<Vec<u8>>,
+
+    // Encoded frames waiting to be flushed to the socket once it becomes
+    // writable.
+    outgoing: VecDeque<Vec<u8>>,
+
+    // Handle for this client's idle-timeout, rescheduled on every read so
+    // the server can tell a dead connection from a merely quiet one.
+    timeout: Option<mio::Timeout>,
 }
 
 impl WebSocketClient {
-    fn read(&mut self) {
+    // Reads whatever is currently available on the socket. Returns `Err` on
+    // a socket error or a protocol violation; the caller is responsible for
+    // closing just this client rather than letting it take down the loop.
+    fn read(&mut self) -> Result<(), ClientError> {
         loop {
             let mut buf = [0; 2048];
-            match self.socket.try_read(&mut buf) {
-                Err(e) => {
-                    println!("Error while reading socket: {:?}", e);
-                    return
-                },
-                Ok(None) =>
+            match try!(self.socket.try_read(&mut buf)) {
+                None =>
                     // Socket buffer has no more bytes.
                     break,
-                Ok(Some(len)) => {
-                    self.http_parser.parse(&buf[0..len]);
-                    if self.http_parser.is_upgrade() {
-                        // Change the current state
-                        self.state = ClientState::HandshakeResponse;
+                Some(len) => {
+                    match self.state {
+                        ClientState::AwaitingHandshake => {
+                            self.http_parser.parse(&buf[0..len]);
+                            if self.http_parser.has_error() {
+                                return Err(ClientError::Protocol("malformed handshake request"));
+                            }
+                            if self.http_parser.is_upgrade() {
+                                // Change the current state
+                                self.state = ClientState::HandshakeResponse;
+
+                                // Change current interest to 'Writable'
+                                self.interest.remove(EventSet::readable());
+                                self.interest.insert(EventSet::writable());
+
+                                break;
+                            }
+                        }
+                        ClientState::Connected => {
+                            self.read_buffer.extend_from_slice(&buf[0..len]);
+                            try!(self.read_frames());
+                        }
+                        ClientState::HandshakeResponse | ClientState::Closing => {}
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
 
-                        // Change current interest to 'Writable'
-                        self.interest.remove(EventSet::readable());
-                        self.interest.insert(EventSet::writable());
+    // Decodes as many complete frames as `read_buffer` currently holds,
+    // reassembling fragmented (continuation) messages along the way.
+    fn read_frames(&mut self) -> Result<(), ClientError> {
+        loop {
+            let (frame, consumed) = match try!(WebSocketFrame::parse(&self.read_buffer)) {
+                Some(result) => result,
+                None => break,
+            };
+
+            self.read_buffer.drain(0..consumed);
+
+            match frame.opcode {
+                OpCode::TextFrame | OpCode::BinaryFrame | OpCode::ContinuationFrame => {
+                    if self.fragment_buffer.len() + frame.payload.len() > MAX_MESSAGE_LEN {
+                        return Err(ClientError::Protocol("reassembled message too large"));
+                    }
 
-                        break;
+                    self.fragment_buffer.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        let message = std::mem::replace(&mut self.fragment_buffer, Vec::new());
+                        self.incoming_messages.push_back(message);
                     }
                 }
+                OpCode::Ping => {
+                    // Ping/Pong are handled transparently here and never
+                    // surfaced to the chat layer.
+                    self.send_frame(OpCode::Pong, &frame.payload);
+                }
+                OpCode::Pong => {
+                    // Either an unsolicited Pong or a reply to a
+                    // server-initiated Ping; nothing to do yet.
+                }
+                OpCode::ConnectionClose => {
+                    // Echo the close frame back and wait for it to drain
+                    // before the server tears the connection down.
+                    self.send_frame(OpCode::ConnectionClose, &frame.payload);
+                    self.state = ClientState::Closing;
+                }
             }
         }
+
+        Ok(())
     }
 
 
-    fn write(&mut self) {
-        // Get the headers HashMap from the Rc<RefCell<...>> wrapper:
-        let headers = self.headers.borrow();
+    fn write(&mut self) -> Result<(), ClientError> {
+        match self.state {
+            ClientState::HandshakeResponse => self.write_handshake_response(),
+            ClientState::Connected | ClientState::Closing => self.flush_outgoing(),
+            ClientState::AwaitingHandshake => Ok(()),
+        }
+    }
 
-        // Find the header that interests us, and generate the key from its value:
-        let response_key = gen_key(&headers.get("Sec-WebSocket-Key").unwrap());
+    fn write_handshake_response(&mut self) -> Result<(), ClientError> {
+        // Missing the one request header the handshake can't do without:
+        // reject instead of unwrap()-ing our way into taking down the
+        // whole event loop.
+        let (response, accepted) = {
+            let headers = self.headers.borrow();
+
+            match headers.get("Sec-WebSocket-Key") {
+                None => (Self::error_response(400, "Bad Request"), false),
+                Some(key) => {
+                    let response_key = gen_key(key);
+
+                    // Let the callback inspect the request headers: it may
+                    // reject the upgrade outright, or accept it and ask for
+                    // extra response headers (e.g. a negotiated
+                    // Sec-WebSocket-Protocol).
+                    match (*self.handshake_callback)(&*headers) {
+                        HandshakeResponse::Reject(status, reason) =>
+                            (Self::error_response(status, &reason), false),
+                        HandshakeResponse::Accept(extra_headers) =>
+                            (Self::success_response(&response_key, &extra_headers), true),
+                    }
+                }
+            }
+        };
+
+        // Queue the response rather than writing it directly: a slow peer
+        // can make even this first reply a partial write, and the outgoing
+        // queue already knows how to retry those.
+        self.outgoing.push_back(response.into_bytes());
+        self.interest.insert(EventSet::writable());
+
+        if accepted {
+            self.state = ClientState::Connected;
+            self.interest.insert(EventSet::readable());
+        } else {
+            // Nothing more to send once the queue drains; the server will
+            // tear the connection down at that point.
+            self.state = ClientState::Closing;
+        }
+
+        self.flush_outgoing()
+    }
 
-        // We're using special function to format the string.
-        // You can find analogies in many other languages, but in Rust it's
-        // performed at the compile time with the power of macros. We'll discuss it
-        // in the next part sometime.
-        let response = fmt::format(format_args!("HTTP/1.1 101 Switching Protocols\r\n\
-                                                Connection: Upgrade\r\n\
-                                                Sec-WebSocket-Accept: {}\r\n\
-                                                Upgrade: websocket\r\n\r\n",
-                                                response_key));
-        // Write the response to the socket:
-        self.socket.try_write(response.as_bytes()).unwrap();
+    // We're using special function to format the string.
+    // You can find analogies in many other languages, but in Rust it's
+    // performed at the compile time with the power of macros. We'll discuss it
+    // in the next part sometime.
+    fn success_response(response_key: &str, extra_headers: &[(String, String)]) -> String {
+        let mut response = fmt::format(format_args!("HTTP/1.1 101 Switching Protocols\r\n\
+                                                    Connection: Upgrade\r\n\
+                                                    Sec-WebSocket-Accept: {}\r\n\
+                                                    Upgrade: websocket\r\n",
+                                                    response_key));
+        for &(ref name, ref value) in extra_headers {
+            response.push_str(&fmt::format(format_args!("{}: {}\r\n", name, value)));
+        }
+        response.push_str("\r\n");
+        response
+    }
 
-        // Change the state:
-        self.state = ClientState::Connected;
+    fn error_response(status: u16, reason: &str) -> String {
+        fmt::format(format_args!("HTTP/1.1 {} {}\r\n\
+                                 Connection: close\r\n\
+                                 Content-Length: 0\r\n\r\n",
+                                 status, reason))
+    }
+
+    // Flushes as much of the outgoing queue as the socket will currently
+    // accept. `try_write` can come back with `Ok(None)` or a short count
+    // when the kernel's send buffer is under backpressure; either way, the
+    // unwritten remainder stays queued for the next writable event instead
+    // of being dropped. The writable interest is only cleared once the
+    // queue is completely empty, since mio is edge-triggered and we won't
+    // get another writable event until there's more buffer space to offer.
+    fn flush_outgoing(&mut self) -> Result<(), ClientError> {
+        while let Some(mut frame) = self.outgoing.pop_front() {
+            match try!(self.socket.try_write(&frame)) {
+                None => {
+                    self.outgoing.push_front(frame);
+                    return Ok(());
+                }
+                Some(written) if written < frame.len() => {
+                    frame.drain(0..written);
+                    self.outgoing.push_front(frame);
+                    return Ok(());
+                }
+                Some(_) => {}
+            }
+        }
 
-        // And change the interest back to 'readable()':
         self.interest.remove(EventSet::writable());
-        self.interest.insert(EventSet::readable());
+        Ok(())
+    }
+
+    // Queues a text message to be sent to this client.
+    fn send_message(&mut self, payload: &[u8]) {
+        self.send_frame(OpCode::TextFrame, payload);
+    }
+
+    fn send_frame(&mut self, opcode: OpCode, payload: &[u8]) {
+        self.outgoing.push_back(WebSocketFrame::encode(opcode, payload));
+        self.interest.insert(EventSet::writable());
     }
 
-    fn new(socket: TcpStream) -> WebSocketClient {
+    fn new(socket: TcpStream, handshake_callback: HandshakeCallback) -> WebSocketClient {
         let headers = Rc::new(RefCell::new(HashMap::new()));
 
         WebSocketClient {
@@ -143,6 +520,14 @@ impl WebSocketClient {
 
             // Initial state
             state: ClientState::AwaitingHandshake,
+
+            handshake_callback: handshake_callback,
+
+            read_buffer: Vec::new(),
+            fragment_buffer: Vec::new(),
+            incoming_messages: VecDeque::new(),
+            outgoing: VecDeque::new(),
+            timeout: None,
         }
     }
 }
@@ -151,10 +536,70 @@ struct WebSocketServer {
     socket: TcpListener,
     clients: HashMap<Token, WebSocketClient>, // Token is a renamed usize imported from mio
     token_counter: usize,
+    handshake_callback: HandshakeCallback,
 }
 
 const SERVER_TOKEN: Token = Token(0);
 
+// How long a client may stay silent before it's considered dead.
+const IDLE_TIMEOUT_MS: u64 = 30_000;
+
+impl WebSocketServer {
+    // Relays a text message from `sender` to every other connected client.
+    fn broadcast(&mut self,
+                 event_loop: &mut EventLoop<WebSocketServer>,
+                 sender: Token,
+                 message: &[u8]) {
+        for (&token, client) in self.clients.iter_mut() {
+            if token == sender {
+                continue;
+            }
+
+            // Only relay to clients that have finished the handshake.
+            // Queuing a raw WS frame onto a client still in
+            // `AwaitingHandshake`/`HandshakeResponse` would land it ahead of
+            // that client's own HTTP 101 response, corrupting the
+            // handshake, and would flag a client in `AwaitingHandshake`
+            // writable even though its `write()` is a no-op in that state.
+            if client.state != ClientState::Connected {
+                continue;
+            }
+
+            client.send_message(message);
+            event_loop.reregister(&client.socket,
+                                  token,
+                                  client.interest,
+                                  PollOpt::edge() | PollOpt::oneshot())
+                      .unwrap();
+        }
+    }
+
+    // (Re)schedules `token`'s idle timeout, cancelling whatever timeout it
+    // already had pending. Called both when a client is first accepted and
+    // whenever it produces read activity.
+    fn reset_timeout(&mut self, event_loop: &mut EventLoop<WebSocketServer>, token: Token) {
+        if let Some(client) = self.clients.get_mut(&token) {
+            if let Some(timeout) = client.timeout.take() {
+                event_loop.clear_timeout(&timeout);
+            }
+
+            client.timeout = event_loop.timeout_ms(token.as_usize(), IDLE_TIMEOUT_MS).ok();
+        }
+    }
+
+    // Tears a single client down: cancels its pending timeout, deregisters
+    // its socket and drops it from `clients`. Used both for a clean Close
+    // handshake and for a client that errored out.
+    fn drop_client(&mut self, event_loop: &mut EventLoop<WebSocketServer>, token: Token) {
+        if let Some(client) = self.clients.remove(&token) {
+            if let Some(timeout) = client.timeout {
+                event_loop.clear_timeout(&timeout);
+            }
+            let _ = event_loop.deregister(&client.socket);
+        }
+    }
+}
+
 impl Handler for WebSocketServer {
     // Traits can have useful default implementations, so in fact the handler interface
     // requires us to provide only two things: concrete types for timeouts and messages.
@@ -181,7 +626,9 @@ impl Handler for WebSocketServer {
                     };
 
                     let new_token = Token(self.token_counter);
-                    self.clients.insert(new_token, WebSocketClient::new(client_socket));
+                    self.clients.insert(new_token,
+                                        WebSocketClient::new(client_socket,
+                                                              self.handshake_callback.clone()));
                     self.token_counter += 1;
 
                     event_loop.register(&self.clients[&new_token].socket,
@@ -189,10 +636,33 @@ impl Handler for WebSocketServer {
                                         EventSet::readable(),
                                         PollOpt::edge() | PollOpt::oneshot())
                               .unwrap();
+
+                    self.reset_timeout(event_loop, new_token);
                 }
                 token => {
-                    let mut client = self.clients.get_mut(&token).unwrap();
-                    client.read();
+                    let mut messages = Vec::new();
+                    let read_result = {
+                        let mut client = self.clients.get_mut(&token).unwrap();
+                        let result = client.read();
+                        while let Some(message) = client.incoming_messages.pop_front() {
+                            messages.push(message);
+                        }
+                        result
+                    };
+
+                    if let Err(e) = read_result {
+                        println!("Closing client {:?} after read error: {:?}", token, e);
+                        self.drop_client(event_loop, token);
+                        return;
+                    }
+
+                    self.reset_timeout(event_loop, token);
+
+                    for message in messages {
+                        self.broadcast(event_loop, token, &message);
+                    }
+
+                    let client = self.clients.get_mut(&token).unwrap();
                     event_loop.reregister(&client.socket,
                                           token,
                                           client.interest,
@@ -205,15 +675,52 @@ impl Handler for WebSocketServer {
         // Handle write events that are generated whenever the socket becomes
         // available for a write operation:
         if events.is_writable() {
-            let mut client = self.clients.get_mut(&token).unwrap();
-            client.write();
-            event_loop.reregister(&client.socket,
-                                  token,
-                                  client.interest,
-                                  PollOpt::edge() | PollOpt::oneshot())
-                      .unwrap();
+            let write_result = {
+                let mut client = self.clients.get_mut(&token).unwrap();
+                client.write()
+            };
+
+            match write_result {
+                Ok(()) => {
+                    let client = self.clients.get_mut(&token).unwrap();
+                    event_loop.reregister(&client.socket,
+                                          token,
+                                          client.interest,
+                                          PollOpt::edge() | PollOpt::oneshot())
+                              .unwrap();
+                }
+                Err(e) => {
+                    println!("Closing client {:?} after write error: {:?}", token, e);
+                    self.drop_client(event_loop, token);
+                    return;
+                }
+            }
+        }
+
+        // Once a client has echoed its Close frame and drained its outgoing
+        // queue, the handshake is complete and the connection can come down.
+        let closed = self.clients.get(&token).map_or(false, |client| {
+            client.state == ClientState::Closing && client.outgoing.is_empty()
+        });
+
+        if closed {
+            self.drop_client(event_loop, token);
         }
     }
+
+    // Fires when a client has gone quiet for longer than `IDLE_TIMEOUT_MS`.
+    // There's no live connection left to have a two-way Close exchange with
+    // by this point, so just best-effort one and tear the client down.
+    fn timeout(&mut self, event_loop: &mut EventLoop<WebSocketServer>, timeout: usize) {
+        let token = Token(timeout);
+
+        if let Some(client) = self.clients.get(&token) {
+            let close_frame = WebSocketFrame::encode(OpCode::ConnectionClose, b"idle timeout");
+            let _ = client.socket.try_write(&close_frame);
+        }
+
+        self.drop_client(event_loop, token);
+    }
 }
 
 fn main() {
@@ -222,10 +729,26 @@ fn main() {
 
     let mut event_loop = EventLoop::new().unwrap();
 
+    // Picks the first subprotocol the client offered, if any. Reject the
+    // upgrade instead if you need authentication or routing at this point.
+    let handshake_callback: HandshakeCallback = Rc::new(|headers: &HashMap<String, String>| {
+        match headers.get("Sec-WebSocket-Protocol") {
+            Some(offered) => {
+                match offered.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).next() {
+                    Some(protocol) =>
+                        HandshakeResponse::Accept(vec![("Sec-WebSocket-Protocol".to_string(), protocol)]),
+                    None => HandshakeResponse::Accept(vec![]),
+                }
+            }
+            None => HandshakeResponse::Accept(vec![]),
+        }
+    });
+
     let mut server = WebSocketServer {
         token_counter: 1, // Starting the token counter from 1
         clients: HashMap::new(), // Creating an empty HashMap
         socket: server_socket, // Handling the ownership of the socket to the struct
+        handshake_callback: handshake_callback,
     };
 
 